@@ -6,17 +6,150 @@
 //! (http://mxr.mozilla.org/mozilla-central/source/toolkit/devtools/server/actors/webconsole.js).
 //! Handles interaction with the remote web console on network events (HTTP requests, responses) in Servo.
 
+extern crate flate2;
 extern crate hyper;
+extern crate time;
 
 use actor::{Actor, ActorMessageStatus, ActorRegistry};
 use devtools_traits::HttpRequest as DevtoolsHttpRequest;
 use devtools_traits::HttpResponse as DevtoolsHttpResponse;
-use hyper::header::Headers;
+use flate2::read::{DeflateDecoder, GzDecoder};
+use hyper::header::Headers as HyperHeaders;
 use hyper::http::RawStatus;
-use hyper::method::Method;
+use hyper::method::Method as HyperMethod;
 use protocol::JsonPacketStream;
-use rustc_serialize::json;
+use rustc_serialize::base64::{STANDARD, ToBase64};
+use serde_json::Value;
+use std::ascii::AsciiExt;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Read;
 use std::net::TcpStream;
+use time::{PreciseTime, Tm};
+
+/// An HTTP method, kept independent of any particular `hyper` version so the actor
+/// and its tests don't depend on a live hyper stack.
+#[derive(Clone, PartialEq)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Connect => "CONNECT",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Patch => "PATCH",
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl<'a> From<&'a HyperMethod> for Method {
+    fn from(method: &'a HyperMethod) -> Method {
+        match *method {
+            HyperMethod::Head => Method::Head,
+            HyperMethod::Post => Method::Post,
+            HyperMethod::Put => Method::Put,
+            HyperMethod::Delete => Method::Delete,
+            HyperMethod::Connect => Method::Connect,
+            HyperMethod::Options => Method::Options,
+            HyperMethod::Trace => Method::Trace,
+            HyperMethod::Patch => Method::Patch,
+            _ => Method::Get,
+        }
+    }
+}
+
+/// An insertion-ordered collection of HTTP headers with case-insensitive name lookup.
+#[derive(Clone)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers { entries: Vec::new() }
+    }
+
+    pub fn push(&mut self, name: String, value: String) {
+        self.entries.push((name, value));
+    }
+
+    /// The first value stored under `name`, matched case-insensitively.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries.iter()
+            .find(|&&(ref n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(_, ref value)| &value[..])
+    }
+
+    /// Every value stored under `name`, matched case-insensitively, in order.
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.entries.iter()
+            .filter(|&&(ref n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(_, ref value)| &value[..])
+            .collect()
+    }
+
+    pub fn iter(&self) -> ::std::slice::Iter<(String, String)> {
+        self.entries.iter()
+    }
+
+    /// Reconstruct the on-the-wire header block, prefixed by `start_line`.
+    pub fn raw(&self, start_line: &str) -> String {
+        let mut raw = start_line.to_owned();
+        for &(ref name, ref value) in &self.entries {
+            raw.push_str(name);
+            raw.push_str(": ");
+            raw.push_str(value);
+            raw.push_str("\r\n");
+        }
+        raw
+    }
+}
+
+impl<'a> From<&'a HyperHeaders> for Headers {
+    fn from(headers: &'a HyperHeaders) -> Headers {
+        let mut result = Headers::new();
+        for header in headers.iter() {
+            result.push(header.name().to_owned(), header.value_string());
+        }
+        result
+    }
+}
+
+/// An HTTP status line, independent of `hyper::http::RawStatus`.
+#[derive(Clone)]
+pub struct Status {
+    pub code: u16,
+    pub reason: String,
+}
+
+impl<'a> From<&'a RawStatus> for Status {
+    fn from(status: &'a RawStatus) -> Status {
+        let RawStatus(code, ref reason) = *status;
+        Status { code: code, reason: reason.to_string() }
+    }
+}
 
 struct HttpRequest {
     url: String,
@@ -25,19 +158,45 @@ struct HttpRequest {
     body: Option<Vec<u8>>,
 }
 
+impl HttpRequest {
+    fn content_type(&self) -> Option<String> {
+        self.headers.get("content-type").map(|value| value.to_owned())
+    }
+}
+
 struct HttpResponse {
     headers: Option<Headers>,
-    status: Option<RawStatus>,
+    status: Option<Status>,
     body: Option<Vec<u8>>
 }
 
+impl HttpResponse {
+    // The `Content-Encoding` applied to the body, lower-cased, if any.
+    fn content_encoding(&self) -> Option<String> {
+        self.headers.as_ref().and_then(|headers| {
+            headers.get("content-encoding").map(|value| value.trim().to_lowercase())
+        })
+    }
+
+    fn content_type(&self) -> Option<String> {
+        self.headers.as_ref().and_then(|headers| {
+            headers.get("content-type").map(|value| value.to_owned())
+        })
+    }
+}
+
 pub struct NetworkEventActor {
     pub name: String,
     request: HttpRequest,
     response: HttpResponse,
+    // Wall-clock time the request was issued, for `startedDateTime`.
+    started_date_time: Tm,
+    // Monotonic instants bracketing the request, used to derive `totalTime`.
+    request_started: PreciseTime,
+    response_received: PreciseTime,
 }
 
-#[derive(RustcEncodable)]
+#[derive(Serialize)]
 pub struct EventActor {
     pub actor: String,
     pub url: String,
@@ -47,7 +206,7 @@ pub struct EventActor {
     pub private: bool
 }
 
-#[derive(RustcEncodable)]
+#[derive(Serialize)]
 pub struct ResponseStartMsg {
     pub httpVersion: String,
     pub remoteAddress: String,
@@ -58,14 +217,173 @@ pub struct ResponseStartMsg {
     pub discardResponseBody: bool,
 }
 
-#[derive(RustcEncodable)]
+#[derive(Serialize)]
+struct Header {
+    name: String,
+    value: String,
+}
+
+#[derive(Serialize)]
 struct GetRequestHeadersReply {
     from: String,
-    headers: Vec<String>,
-    headerSize: u8,
+    headers: Vec<Header>,
+    headerSize: usize,
+    rawHeaders: String
+}
+
+#[derive(Serialize)]
+struct GetResponseHeadersReply {
+    from: String,
+    headers: Vec<Header>,
+    headerSize: usize,
     rawHeaders: String
 }
 
+#[derive(Serialize)]
+struct Timings {
+    blocked: u32,
+    dns: u32,
+    connect: u32,
+    send: u32,
+    wait: u32,
+    receive: u32,
+}
+
+#[derive(Serialize)]
+struct GetEventTimingsReply {
+    from: String,
+    timings: Timings,
+    totalTime: u64,
+}
+
+#[derive(Serialize)]
+struct Cookie {
+    name: String,
+    value: String,
+}
+
+#[derive(Serialize)]
+struct ResponseCookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    expires: Option<String>,
+    httpOnly: bool,
+    secure: bool,
+}
+
+#[derive(Serialize)]
+struct RequestCookiesReply {
+    from: String,
+    cookies: Vec<Cookie>,
+}
+
+#[derive(Serialize)]
+struct ResponseCookiesReply {
+    from: String,
+    cookies: Vec<ResponseCookie>,
+}
+
+#[derive(Serialize)]
+struct GetRequestPostDataReply {
+    from: String,
+    postData: String,
+    postDataSize: usize,
+    contentType: Option<String>,
+    postDataDiscarded: bool,
+}
+
+#[derive(Serialize)]
+struct GetResponseContentReply {
+    from: String,
+    mimeType: String,
+    content: String,
+    contentSize: usize,
+    contentAvailable: bool,
+}
+
+// Produce the structured `{name, value}` entries the devtools Headers panel expects,
+// alongside a faithful reconstruction of the on-the-wire header block (prefixed by
+// `start_line`) and its byte length.
+fn collect_headers(headers: &Headers, start_line: &str) -> (Vec<Header>, String, usize) {
+    let entries = headers.iter().map(|&(ref name, ref value)| {
+        Header { name: name.clone(), value: value.clone() }
+    }).collect();
+    let raw = headers.raw(start_line);
+    let header_size = raw.len();
+    (entries, raw, header_size)
+}
+
+// Split a single `Set-Cookie` header value into its name/value pair and attributes.
+fn parse_set_cookie(value: &str) -> Option<ResponseCookie> {
+    let mut parts = value.split(';');
+    let mut pair = match parts.next() {
+        Some(pair) => pair.splitn(2, '='),
+        None => return None,
+    };
+    let name = match pair.next() {
+        Some(name) => name.trim().to_owned(),
+        None => return None,
+    };
+    let value = match pair.next() {
+        Some(value) => value.trim().to_owned(),
+        None => return None,
+    };
+
+    let mut cookie = ResponseCookie {
+        name: name,
+        value: value,
+        path: None,
+        domain: None,
+        expires: None,
+        httpOnly: false,
+        secure: false,
+    };
+    for attribute in parts {
+        let mut attribute = attribute.splitn(2, '=');
+        let key = match attribute.next() {
+            Some(key) => key.trim().to_lowercase(),
+            None => continue,
+        };
+        let val = attribute.next().map(|v| v.trim().to_owned());
+        match &key[..] {
+            "path" => cookie.path = val,
+            "domain" => cookie.domain = val,
+            "expires" => cookie.expires = val,
+            "httponly" => cookie.httpOnly = true,
+            "secure" => cookie.secure = true,
+            _ => {}
+        }
+    }
+    Some(cookie)
+}
+
+// Parse one `Cookie` header value (semicolon-separated `name=value` pairs).
+fn parse_cookie_header(value: &str) -> Vec<Cookie> {
+    value.split(';').filter_map(|pair| {
+        let mut pair = pair.splitn(2, '=');
+        let name = match pair.next() {
+            Some(name) => name.trim().to_owned(),
+            None => return None,
+        };
+        pair.next().map(|value| Cookie {
+            name: name,
+            value: value.trim().to_owned(),
+        })
+    }).collect()
+}
+
+// A MIME type whose body is best surfaced to the console as text rather than base64.
+fn is_text_mime_type(mime_type: &str) -> bool {
+    let mime_type = mime_type.to_lowercase();
+    mime_type.starts_with("text/") ||
+    mime_type.contains("json") ||
+    mime_type.contains("xml") ||
+    mime_type.contains("javascript") ||
+    mime_type.contains("html")
+}
+
 impl Actor for NetworkEventActor {
     fn name(&self) -> String {
         self.name.clone()
@@ -74,34 +392,43 @@ impl Actor for NetworkEventActor {
     fn handle_message(&self,
                       _registry: &ActorRegistry,
                       msg_type: &str,
-                      _msg: &json::Object,
+                      _msg: &BTreeMap<String, Value>,
                       stream: &mut TcpStream) -> Result<ActorMessageStatus, ()> {
         Ok(match msg_type {
             "getRequestHeaders" => {
-                // TODO: Pass the correct values for headers, headerSize, rawHeaders
-                let msg = GetRequestHeadersReply {
-                    from: self.name(),
-                    headers: Vec::new(),
-                    headerSize: 10,
-                    rawHeaders: "Raw headers".to_owned(),
-                };
+                let msg = self.request_headers();
                 stream.write_json_packet(&msg);
                 ActorMessageStatus::Processed
             }
             "getRequestCookies" => {
-                ActorMessageStatus::Ignored
+                let msg = self.request_cookies();
+                stream.write_json_packet(&msg);
+                ActorMessageStatus::Processed
             }
             "getRequestPostData" => {
-                ActorMessageStatus::Ignored
+                let msg = self.request_post_data();
+                stream.write_json_packet(&msg);
+                ActorMessageStatus::Processed
             }
             "getResponseHeaders" => {
-                ActorMessageStatus::Ignored
+                let msg = self.response_headers();
+                stream.write_json_packet(&msg);
+                ActorMessageStatus::Processed
             }
             "getResponseCookies" => {
-                ActorMessageStatus::Ignored
+                let msg = self.response_cookies();
+                stream.write_json_packet(&msg);
+                ActorMessageStatus::Processed
+            }
+            "getEventTimings" => {
+                let msg = self.event_timings();
+                stream.write_json_packet(&msg);
+                ActorMessageStatus::Processed
             }
             "getResponseContent" => {
-                ActorMessageStatus::Ignored
+                let msg = self.response_content();
+                stream.write_json_packet(&msg);
+                ActorMessageStatus::Processed
             }
             _ => ActorMessageStatus::Ignored
         })
@@ -110,6 +437,7 @@ impl Actor for NetworkEventActor {
 
 impl NetworkEventActor {
     pub fn new(name: String) -> NetworkEventActor {
+        let now = PreciseTime::now();
         NetworkEventActor {
             name: name,
             request: HttpRequest {
@@ -122,46 +450,216 @@ impl NetworkEventActor {
                 headers: None,
                 status: None,
                 body: None,
-            }
+            },
+            started_date_time: time::now_utc(),
+            request_started: now,
+            response_received: now,
         }
     }
 
     pub fn add_request(&mut self, request: DevtoolsHttpRequest) {
         self.request.url = request.url.serialize();
-        self.request.method = request.method.clone();
-        self.request.headers = request.headers.clone();
+        self.request.method = Method::from(&request.method);
+        self.request.headers = Headers::from(&request.headers);
         self.request.body = request.body;
+        self.started_date_time = time::now_utc();
+        self.request_started = PreciseTime::now();
     }
 
     pub fn add_response(&mut self, response: DevtoolsHttpResponse) {
-        self.response.headers = response.headers.clone();
-        self.response.status = response.status.clone();
+        self.response.headers = response.headers.as_ref().map(Headers::from);
+        self.response.status = response.status.as_ref().map(Status::from);
         self.response.body = response.body.clone();
+        self.response_received = PreciseTime::now();
      }
 
     pub fn event_actor(&self) -> EventActor {
-        // TODO: Send the correct values for startedDateTime, isXHR, private
+        // TODO: Send the correct values for isXHR, private
         EventActor {
             actor: self.name(),
             url: self.request.url.clone(),
             method: format!("{}", self.request.method),
-            startedDateTime: "2015-04-22T20:47:08.545Z".to_owned(),
+            startedDateTime: format!("{}", self.started_date_time.rfc3339()),
             isXHR: false,
             private: false,
         }
     }
 
+    pub fn event_timings(&self) -> GetEventTimingsReply {
+        // Only the overall duration is measured so far; the individual phases
+        // (blocked/dns/connect/send/wait/receive) are reported as zero until the
+        // network stack threads finer-grained instrumentation through to devtools.
+        let total_time = self.request_started.to(self.response_received)
+                                             .num_milliseconds();
+        GetEventTimingsReply {
+            from: self.name(),
+            timings: Timings {
+                blocked: 0,
+                dns: 0,
+                connect: 0,
+                send: 0,
+                wait: 0,
+                receive: 0,
+            },
+            totalTime: total_time as u64,
+        }
+    }
+
+    pub fn request_post_data(&self) -> GetRequestPostDataReply {
+        let content_type = self.request.content_type();
+        let body = match self.request.body {
+            Some(ref body) => body.clone(),
+            None => return GetRequestPostDataReply {
+                from: self.name(),
+                postData: "".to_owned(),
+                postDataSize: 0,
+                contentType: content_type,
+                postDataDiscarded: true,
+            }
+        };
+
+        // A declared charset means the payload is textual; otherwise fall back to base64.
+        let is_text = content_type.as_ref()
+            .map_or(false, |ct| ct.to_lowercase().contains("charset"));
+        // The size is the raw payload byte count, not the length of a base64 string.
+        let post_data_size = body.len();
+        let post_data = if is_text {
+            String::from_utf8_lossy(&body).into_owned()
+        } else {
+            body.to_base64(STANDARD)
+        };
+
+        GetRequestPostDataReply {
+            from: self.name(),
+            postDataSize: post_data_size,
+            postData: post_data,
+            contentType: content_type,
+            postDataDiscarded: false,
+        }
+    }
+
+    pub fn request_cookies(&self) -> RequestCookiesReply {
+        let mut cookies = Vec::new();
+        for value in self.request.headers.get_all("cookie") {
+            cookies.extend(parse_cookie_header(value));
+        }
+        RequestCookiesReply {
+            from: self.name(),
+            cookies: cookies,
+        }
+    }
+
+    pub fn response_cookies(&self) -> ResponseCookiesReply {
+        let mut cookies = Vec::new();
+        if let Some(ref headers) = self.response.headers {
+            for value in headers.get_all("set-cookie") {
+                if let Some(cookie) = parse_set_cookie(value) {
+                    cookies.push(cookie);
+                }
+            }
+        }
+        ResponseCookiesReply {
+            from: self.name(),
+            cookies: cookies,
+        }
+    }
+
+    pub fn request_headers(&self) -> GetRequestHeadersReply {
+        let start_line = format!("{} {} HTTP/1.1\r\n", self.request.method, self.request.url);
+        let (headers, raw_headers, header_size) = collect_headers(&self.request.headers, &start_line);
+        GetRequestHeadersReply {
+            from: self.name(),
+            headers: headers,
+            headerSize: header_size,
+            rawHeaders: raw_headers,
+        }
+    }
+
+    pub fn response_headers(&self) -> GetResponseHeadersReply {
+        let start_line = match self.response.status {
+            Some(ref status) => format!("HTTP/1.1 {} {}\r\n", status.code, status.reason),
+            None => "".to_owned(),
+        };
+        let (headers, raw_headers, header_size) = match self.response.headers {
+            Some(ref headers) => collect_headers(headers, &start_line),
+            None => (Vec::new(), start_line.clone(), start_line.len()),
+        };
+        GetResponseHeadersReply {
+            from: self.name(),
+            headers: headers,
+            headerSize: header_size,
+            rawHeaders: raw_headers,
+        }
+    }
+
+    pub fn response_content(&self) -> GetResponseContentReply {
+        let mime_type = self.response.content_type().unwrap_or("".to_owned());
+        let body = match self.response.body {
+            Some(ref body) => body.clone(),
+            None => return GetResponseContentReply {
+                from: self.name(),
+                mimeType: mime_type,
+                content: "".to_owned(),
+                contentSize: 0,
+                contentAvailable: false,
+            }
+        };
+
+        // Transparently inflate bodies stored under a `Content-Encoding` we understand.
+        let decoded = match self.response.content_encoding() {
+            Some(ref encoding) if encoding == "gzip" => {
+                // The body is server-controlled and may be truncated or mislabelled, so a
+                // failed header read must not panic the devtools thread: fall back to the
+                // raw bytes. A read error after the header keeps whatever was inflated.
+                match GzDecoder::new(&body[..]) {
+                    Ok(mut decoder) => {
+                        let mut inflated = Vec::new();
+                        decoder.read_to_end(&mut inflated).ok();
+                        inflated
+                    }
+                    Err(_) => body,
+                }
+            }
+            Some(ref encoding) if encoding == "deflate" => {
+                let mut decoder = DeflateDecoder::new(&body[..]);
+                let mut inflated = Vec::new();
+                decoder.read_to_end(&mut inflated).ok();
+                inflated
+            }
+            _ => body,
+        };
+
+        // Report the decoded (inflated) byte count, not the length of a base64 string.
+        let content_size = decoded.len();
+        let content = if is_text_mime_type(&mime_type) {
+            String::from_utf8_lossy(&decoded).into_owned()
+        } else {
+            decoded.to_base64(STANDARD)
+        };
+
+        GetResponseContentReply {
+            from: self.name(),
+            mimeType: mime_type,
+            contentSize: content_size,
+            content: content,
+            contentAvailable: true,
+        }
+    }
+
     pub fn response_start(&self) -> ResponseStartMsg {
-        // TODO: Send the correct values for all these fields.
-        //       This is a fake message.
+        // TODO: Send the correct values for remoteAddress and remotePort.
+        let (status, status_text) = match self.response.status {
+            Some(ref status) => (status.code.to_string(), status.reason.clone()),
+            None => ("".to_owned(), "".to_owned()),
+        };
         ResponseStartMsg {
             httpVersion: "HTTP/1.1".to_owned(),
             remoteAddress: "63.245.217.43".to_owned(),
             remotePort: 443,
-            status: "200".to_owned(),
-            statusText: "OK".to_owned(),
-            headersSize: 337,
-            discardResponseBody: true
+            status: status,
+            statusText: status_text,
+            headersSize: self.response_headers().headerSize as u32,
+            discardResponseBody: false,
         }
     }
 }
@@ -2,13 +2,33 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+extern crate gif;
+
+use self::gif::{ColorOutput, DisposalMethod, SetParameter};
 use ipc_channel::ipc::IpcSharedMemory;
 use piston_image::{self, DynamicImage, GenericImage};
 use stb_image::image as stb_image2;
+use std::io::Cursor;
 use util::vec::byte_swap;
 
 pub use msg::constellation_msg::{Image, PixelFormat};
 
+/// A single composited frame of an animated image.
+pub struct AnimatedFrame {
+    /// The fully-composited, premultiplied RGBA8 pixels of the frame.
+    pub bytes: IpcSharedMemory,
+    /// How long the frame should be shown, in milliseconds.
+    pub delay: u32,
+}
+
+/// A decoded animated image (e.g. an animated GIF or APNG): a sequence of frames
+/// composited onto a shared canvas.
+pub struct AnimatedImage {
+    pub width: u32,
+    pub height: u32,
+    pub frames: Vec<AnimatedFrame>,
+}
+
 // FIXME: Images must not be copied every frame. Instead we should atomically
 // reference count them.
 
@@ -31,9 +51,10 @@ pub fn load_from_memory(buffer: &[u8]) -> Option<Image> {
         return None;
     }
 
-    if is_jpeg(buffer) {
+    if is_jpeg(buffer) || is_hdr(buffer) {
         // For JPEG images, we use stb_image because piston_image does not yet support progressive
-        // JPEG.
+        // JPEG. Radiance/`.hdr` content is also routed here because piston_image cannot decode it
+        // and stb_image hands it back as linear floats for us to tonemap below.
 
         // Can't remember why we do this. Maybe it's what cairo wants
         static FORCE_DEPTH: usize = 4;
@@ -54,9 +75,32 @@ pub fn load_from_memory(buffer: &[u8]) -> Option<Image> {
                     bytes: IpcSharedMemory::from_bytes(&image.data[..]),
                 })
             }
-            stb_image2::LoadResult::ImageF32(_image) => {
-                debug!("HDR images not implemented");
-                None
+            stb_image2::LoadResult::ImageF32(image) => {
+                // Radiance/HDR content is decoded as linear floats. Tonemap it down to
+                // the 8-bit RGBA path with Reinhard tonemapping followed by an sRGB-style
+                // gamma, filling the alpha channel opaque.
+                let depth = image.depth;
+                let mut data = Vec::with_capacity(image.width * image.height * 4);
+                for pixel in image.data.chunks(depth) {
+                    // Emit RGBA explicitly regardless of the source channel count: tonemap
+                    // the (up to three) colour channels and always append an opaque alpha
+                    // byte, so the buffer is exactly 4 bytes/pixel for `byte_swap_and_premultiply`.
+                    for channel in 0..3 {
+                        let value = pixel.get(channel).cloned().unwrap_or(0.0);
+                        let tonemapped = value / (1.0 + value);
+                        let gamma = tonemapped.powf(1.0 / 2.2);
+                        let clamped = gamma.max(0.0).min(1.0);
+                        data.push((clamped * 255.0) as u8);
+                    }
+                    data.push(255);
+                }
+                byte_swap_and_premultiply(&mut data);
+                Some(Image {
+                    width: image.width as u32,
+                    height: image.height as u32,
+                    format: PixelFormat::RGBA8,
+                    bytes: IpcSharedMemory::from_bytes(&data[..]),
+                })
             }
             stb_image2::LoadResult::Error(e) => {
                 debug!("stb_image failed: {}", e);
@@ -86,6 +130,110 @@ pub fn load_from_memory(buffer: &[u8]) -> Option<Image> {
     }
 }
 
+/// Decode an animated GIF into its composited frames.
+///
+/// Each raw frame is composited onto a running canvas honoring its x/y offset and
+/// disposal method: `None`/`DoNotDispose` (`Keep`) leaves the pixels in place,
+/// `Background` clears the frame rect to transparent, and `Previous` restores the
+/// canvas snapshot taken before the frame was drawn. Still images are handled by
+/// `load_from_memory`. This returns `None` for input that is not a GIF or fails to
+/// decode; a GIF with a single frame yields an `AnimatedImage` with one frame.
+pub fn load_animated_from_memory(buffer: &[u8]) -> Option<AnimatedImage> {
+    if !is_gif(buffer) {
+        return None;
+    }
+
+    let mut decoder = gif::Decoder::new(Cursor::new(buffer));
+    decoder.set(ColorOutput::RGBA);
+    let mut reader = match decoder.read_info() {
+        Ok(reader) => reader,
+        Err(e) => {
+            debug!("gif decoding error: {:?}", e);
+            return None;
+        }
+    };
+
+    let width = reader.width() as u32;
+    let height = reader.height() as u32;
+    // Compute the canvas size in `usize`: the screen dimensions are attacker-controlled
+    // (up to 65535×65535) and `width * height * 4` overflows `u32`.
+    let mut canvas = vec![0u8; (width as usize) * (height as usize) * 4];
+    let mut frames = Vec::new();
+
+    loop {
+        let frame = match reader.read_next_frame() {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(e) => {
+                debug!("gif decoding error: {:?}", e);
+                return None;
+            }
+        };
+
+        let (fx, fy) = (frame.left as u32, frame.top as u32);
+        let (fw, fh) = (frame.width as u32, frame.height as u32);
+
+        // Snapshot the canvas before drawing, in case this frame's disposal is `Previous`.
+        let snapshot = canvas.clone();
+
+        // Composite the frame's pixels onto the canvas, skipping transparent ones so
+        // the previously-drawn contents show through.
+        for row in 0..fh {
+            for col in 0..fw {
+                let src = ((row as usize * fw as usize) + col as usize) * 4;
+                let dest = (((fy as usize + row as usize) * width as usize) +
+                            (fx as usize + col as usize)) * 4;
+                if src + 4 > frame.buffer.len() || dest + 4 > canvas.len() {
+                    continue;
+                }
+                if frame.buffer[src + 3] == 0 {
+                    continue;
+                }
+                for i in 0..4 {
+                    canvas[dest + i] = frame.buffer[src + i];
+                }
+            }
+        }
+
+        let mut composited = canvas.clone();
+        byte_swap_and_premultiply(&mut composited);
+        frames.push(AnimatedFrame {
+            bytes: IpcSharedMemory::from_bytes(&composited[..]),
+            // GIF delays are stored in hundredths of a second.
+            delay: (frame.delay as u32) * 10,
+        });
+
+        match frame.dispose {
+            DisposalMethod::Background => {
+                for row in 0..fh {
+                    for col in 0..fw {
+                        let dest = (((fy as usize + row as usize) * width as usize) +
+                                    (fx as usize + col as usize)) * 4;
+                        if dest + 4 <= canvas.len() {
+                            for i in 0..4 {
+                                canvas[dest + i] = 0;
+                            }
+                        }
+                    }
+                }
+            }
+            DisposalMethod::Previous => canvas = snapshot,
+            // `Any`/`Keep` correspond to None/DoNotDispose: keep the drawn pixels.
+            _ => {}
+        }
+    }
+
+    if frames.is_empty() {
+        None
+    } else {
+        Some(AnimatedImage {
+            width: width,
+            height: height,
+            frames: frames,
+        })
+    }
+}
+
 fn is_gif(buffer: &[u8]) -> bool {
     match buffer {
         [b'G', b'I', b'F', b'8', n, b'a', ..] if n == b'7' || n == b'9' => true,
@@ -96,3 +244,7 @@ fn is_gif(buffer: &[u8]) -> bool {
 fn is_jpeg(buffer: &[u8]) -> bool {
     buffer.starts_with(&[0xff, 0xd8, 0xff])
 }
+
+fn is_hdr(buffer: &[u8]) -> bool {
+    buffer.starts_with(b"#?RADIANCE") || buffer.starts_with(b"#?RGBE")
+}
@@ -5,6 +5,7 @@
 use cssparser::{Delimiter, Parser, SourcePosition, ToCss, Token, TokenSerializationType};
 use properties::DeclaredValue;
 use std::ascii::AsciiExt;
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::sync::Arc;
@@ -60,7 +61,114 @@ impl ToCss for ComputedValue {
     }
 }
 
-pub type ComputedValuesMap = HashMap<Name, ComputedValue>;
+/// A map from custom property names to their (computed) values, preserving the
+/// order in which the names were inserted during the cascade.
+///
+/// CSSOM enumeration (`getComputedStyle().getPropertyValue("--x")`) and declaration
+/// block serialization must be deterministic, so a plain `HashMap` (whose iteration
+/// order is unspecified and varies between runs) is not enough: we keep an explicit
+/// `index` of the names alongside the `HashMap` used for lookup.
+#[derive(Clone, HeapSizeOf)]
+pub struct OrderedMap<V> {
+    /// Custom property names, in insertion (cascade) order.
+    index: Vec<Name>,
+    /// Values, indexed by custom property name.
+    values: HashMap<Name, V>,
+}
+
+impl<V> OrderedMap<V> {
+    pub fn new() -> OrderedMap<V> {
+        OrderedMap {
+            index: Vec::new(),
+            values: HashMap::new(),
+        }
+    }
+
+    /// Insert a value, recording its name in cascade order the first time it is seen.
+    pub fn insert(&mut self, name: Name, value: V) {
+        if !self.values.contains_key(&name) {
+            self.index.push(name.clone());
+        }
+        self.values.insert(name, value);
+    }
+
+    pub fn get(&self, name: &Name) -> Option<&V> {
+        self.values.get(name)
+    }
+
+    pub fn contains_key(&self, name: &Name) -> bool {
+        self.values.contains_key(name)
+    }
+
+    /// Remove a value, keeping the `index` consistent with `values`.
+    pub fn remove(&mut self, name: &Name) -> Option<V> {
+        if let Some(position) = self.index.iter().position(|n| n == name) {
+            self.index.remove(position);
+        }
+        self.values.remove(name)
+    }
+
+    /// Iterate over the entries in reverse cascade order (most recently inserted first).
+    pub fn iter(&self) -> OrderedMapIterator<V> {
+        OrderedMapIterator {
+            inner: self,
+            pos: self.index.len(),
+        }
+    }
+
+    /// Iterate over the entries in forward cascade (insertion) order.
+    ///
+    /// Used when seeding a child element from its inherited properties: the order
+    /// must be preserved down the tree so CSSOM enumeration stays stable across
+    /// inheritance generations.
+    pub fn iter_forward(&self) -> OrderedMapForwardIterator<V> {
+        OrderedMapForwardIterator {
+            inner: self,
+            pos: 0,
+        }
+    }
+}
+
+/// An iterator over an `OrderedMap`, yielding entries in reverse cascade order.
+pub struct OrderedMapIterator<'a, V: 'a> {
+    inner: &'a OrderedMap<V>,
+    pos: usize,
+}
+
+impl<'a, V> Iterator for OrderedMapIterator<'a, V> {
+    type Item = (&'a Name, &'a V);
+
+    fn next(&mut self) -> Option<(&'a Name, &'a V)> {
+        if self.pos == 0 {
+            return None;
+        }
+        self.pos -= 1;
+        let name = &self.inner.index[self.pos];
+        Some((name, self.inner.values.get(name).unwrap()))
+    }
+}
+
+/// An iterator over an `OrderedMap`, yielding entries in forward cascade order.
+pub struct OrderedMapForwardIterator<'a, V: 'a> {
+    inner: &'a OrderedMap<V>,
+    pos: usize,
+}
+
+impl<'a, V> Iterator for OrderedMapForwardIterator<'a, V> {
+    type Item = (&'a Name, &'a V);
+
+    fn next(&mut self) -> Option<(&'a Name, &'a V)> {
+        if self.pos >= self.inner.index.len() {
+            return None;
+        }
+        let name = &self.inner.index[self.pos];
+        self.pos += 1;
+        Some((name, self.inner.values.get(name).unwrap()))
+    }
+}
+
+/// The computed custom properties of an element, in deterministic cascade order.
+pub type ComputedValuesMap = OrderedMap<ComputedValue>;
 
 impl ComputedValue {
     fn empty() -> ComputedValue {
@@ -93,6 +201,299 @@ impl ComputedValue {
     }
 }
 
+/// A single component type in a registered custom property's `syntax` grammar.
+/// https://drafts.css-houdini.org/css-properties-values-api/#supported-syntax-strings
+#[derive(Clone, PartialEq)]
+pub enum ComponentType {
+    Length,
+    Percentage,
+    LengthPercentage,
+    Number,
+    Integer,
+    Color,
+    Angle,
+    Time,
+    Url,
+    Image,
+    CustomIdent,
+}
+
+impl ComponentType {
+    fn from_str(s: &str) -> Option<ComponentType> {
+        Some(match s {
+            "<length>" => ComponentType::Length,
+            "<percentage>" => ComponentType::Percentage,
+            "<length-percentage>" => ComponentType::LengthPercentage,
+            "<number>" => ComponentType::Number,
+            "<integer>" => ComponentType::Integer,
+            "<color>" => ComponentType::Color,
+            "<angle>" => ComponentType::Angle,
+            "<time>" => ComponentType::Time,
+            "<url>" => ComponentType::Url,
+            "<image>" => ComponentType::Image,
+            // A custom ident: a non-empty name that is not an (unknown) data type.
+            _ if !s.is_empty() && !s.starts_with('<') => ComponentType::CustomIdent,
+            _ => return None,
+        })
+    }
+
+    /// Consume and validate a single value of this type from `input`.
+    fn parse_one(&self, input: &mut Parser) -> Result<(), ()> {
+        match *self {
+            ComponentType::Number => {
+                try!(input.expect_number());
+                Ok(())
+            }
+            ComponentType::Integer => {
+                match try!(input.next()) {
+                    Token::Number(ref value) if value.int_value.is_some() => Ok(()),
+                    _ => Err(()),
+                }
+            }
+            ComponentType::Percentage => {
+                try!(input.expect_percentage());
+                Ok(())
+            }
+            ComponentType::Length | ComponentType::Angle | ComponentType::Time => {
+                match try!(input.next()) {
+                    Token::Dimension(..) => Ok(()),
+                    Token::Number(ref value) if value.value == 0. => Ok(()),
+                    _ => Err(()),
+                }
+            }
+            ComponentType::LengthPercentage => {
+                match try!(input.next()) {
+                    Token::Dimension(..) |
+                    Token::Percentage(..) => Ok(()),
+                    Token::Number(ref value) if value.value == 0. => Ok(()),
+                    _ => Err(()),
+                }
+            }
+            ComponentType::Color => {
+                // A bare ident is only a color if it names one (a keyword such as `red`,
+                // `transparent` or `currentcolor`), and a function is only a color if it
+                // is one of the color-producing functions. This rejects `banana` and
+                // arbitrary `foo(...)`, unlike a blanket accept of any ident/function.
+                // Note: the keyword list is the common set, not the full CSS named-color
+                // table, so a few exotic names may still be rejected.
+                match try!(input.next()) {
+                    Token::Ident(ref name) if is_color_keyword(name) => Ok(()),
+                    Token::Hash(_) |
+                    Token::IDHash(_) => Ok(()),
+                    Token::Function(ref name) if is_color_function(name) => {
+                        input.parse_nested_block(|input| {
+                            while input.next().is_ok() {}
+                            Ok(())
+                        })
+                    }
+                    _ => Err(()),
+                }
+            }
+            ComponentType::Url | ComponentType::Image => {
+                match try!(input.next()) {
+                    Token::UnquotedUrl(_) => Ok(()),
+                    Token::Function(_) => input.parse_nested_block(|input| {
+                        while input.next().is_ok() {}
+                        Ok(())
+                    }),
+                    _ => Err(()),
+                }
+            }
+            ComponentType::CustomIdent => {
+                try!(input.expect_ident());
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Whether `name` is one of the CSS color keywords we recognize for `<color>`
+/// validation (the common set plus the special `transparent`/`currentcolor`).
+fn is_color_keyword(name: &str) -> bool {
+    const KEYWORDS: &'static [&'static str] = &[
+        "transparent", "currentcolor",
+        "black", "silver", "gray", "grey", "white", "maroon", "red", "purple",
+        "fuchsia", "green", "lime", "olive", "yellow", "navy", "blue", "teal",
+        "aqua", "orange", "cyan", "magenta", "pink", "brown",
+    ];
+    KEYWORDS.iter().any(|k| name.eq_ignore_ascii_case(k))
+}
+
+/// Whether `name` is a color-producing function accepted for `<color>` validation.
+fn is_color_function(name: &str) -> bool {
+    const FUNCTIONS: &'static [&'static str] = &["rgb", "rgba", "hsl", "hsla", "color"];
+    FUNCTIONS.iter().any(|f| name.eq_ignore_ascii_case(f))
+}
+
+/// How a `syntax` component may repeat.
+#[derive(Clone, PartialEq)]
+pub enum Multiplier {
+    /// No multiplier: exactly one value.
+    One,
+    /// `+`: a space-separated list of one or more values.
+    Space,
+    /// `#`: a comma-separated list of one or more values.
+    Comma,
+}
+
+#[derive(Clone)]
+pub struct SyntaxComponent {
+    ty: ComponentType,
+    multiplier: Multiplier,
+}
+
+impl SyntaxComponent {
+    fn validate(&self, css: &str) -> bool {
+        let mut input = Parser::new(css);
+        let result = match self.multiplier {
+            Multiplier::One => self.ty.parse_one(&mut input),
+            Multiplier::Space => {
+                let mut parsed = false;
+                while !input.is_exhausted() {
+                    if self.ty.parse_one(&mut input).is_err() {
+                        return false;
+                    }
+                    parsed = true;
+                }
+                if parsed { Ok(()) } else { Err(()) }
+            }
+            Multiplier::Comma => {
+                // Each comma-delimited segment must be fully consumed: `parse_one` eats
+                // exactly one value, so without `expect_exhausted` a segment like `1 2`
+                // would be silently accepted.
+                input.parse_comma_separated(|input| {
+                    self.ty.parse_one(input)
+                        .and_then(|_| input.expect_exhausted().map_err(|_| ()))
+                }).map(|_| ())
+            }
+        };
+        result.is_ok() && input.is_exhausted()
+    }
+}
+
+/// A parsed `syntax` grammar of a registered custom property.
+#[derive(Clone)]
+pub enum Syntax {
+    /// The universal syntax `*`: any valid declaration value.
+    Universal,
+    /// One of a set of `|`-separated alternatives.
+    Alternatives(Vec<SyntaxComponent>),
+}
+
+/// Parse a `syntax` string such as `"<length>+"` or `"<color> | <number>"`.
+pub fn parse_syntax(syntax: &str) -> Result<Syntax, ()> {
+    let syntax = syntax.trim();
+    if syntax == "*" {
+        return Ok(Syntax::Universal);
+    }
+    let mut alternatives = Vec::new();
+    for alternative in syntax.split('|') {
+        let alternative = alternative.trim();
+        let (name, multiplier) = if alternative.ends_with('+') {
+            (&alternative[..alternative.len() - 1], Multiplier::Space)
+        } else if alternative.ends_with('#') {
+            (&alternative[..alternative.len() - 1], Multiplier::Comma)
+        } else {
+            (alternative, Multiplier::One)
+        };
+        let ty = try!(ComponentType::from_str(name.trim()).ok_or(()));
+        alternatives.push(SyntaxComponent { ty: ty, multiplier: multiplier });
+    }
+    if alternatives.is_empty() {
+        Err(())
+    } else {
+        Ok(Syntax::Alternatives(alternatives))
+    }
+}
+
+impl Syntax {
+    /// Whether `css` is a valid value for this syntax.
+    pub fn validate(&self, css: &str) -> bool {
+        match *self {
+            Syntax::Universal => true,
+            Syntax::Alternatives(ref alternatives) => {
+                alternatives.iter().any(|component| component.validate(css))
+            }
+        }
+    }
+}
+
+/// The registration of a custom property via `@property` or the
+/// Properties & Values API.
+#[derive(Clone)]
+pub struct PropertyRegistration {
+    pub syntax: Syntax,
+    pub inherits: bool,
+    pub initial: Option<ComputedValue>,
+}
+
+impl PropertyRegistration {
+    /// Build a registration from the three `@property` descriptors (or the equivalent
+    /// `registerProperty()` arguments): the `syntax` string, whether the property
+    /// `inherits`, and the `initial-value` CSS (required unless the syntax is `*`).
+    ///
+    /// Per the Properties & Values API, a non-universal syntax must have an
+    /// `initial-value` that parses against it; a missing or invalid initial value
+    /// makes the registration invalid.
+    pub fn parse(syntax: &str, inherits: bool, initial: Option<&str>)
+                 -> Result<PropertyRegistration, ()> {
+        let syntax = try!(parse_syntax(syntax));
+        let initial = match (&syntax, initial) {
+            (&Syntax::Universal, initial) => initial.map(|css| ComputedValue {
+                css: css.to_owned(),
+                first_token_type: TokenSerializationType::nothing(),
+                last_token_type: TokenSerializationType::nothing(),
+            }),
+            (_, Some(css)) if syntax.validate(css) => Some(ComputedValue {
+                css: css.to_owned(),
+                first_token_type: TokenSerializationType::nothing(),
+                last_token_type: TokenSerializationType::nothing(),
+            }),
+            // A non-universal syntax with no (valid) initial value is invalid.
+            (_, _) => return Err(()),
+        };
+        Ok(PropertyRegistration {
+            syntax: syntax,
+            inherits: inherits,
+            initial: initial,
+        })
+    }
+}
+
+/// A set of registered custom properties, keyed by name (without the `--` prefix).
+#[derive(Clone)]
+pub struct Registry {
+    properties: HashMap<Name, PropertyRegistration>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry { properties: HashMap::new() }
+    }
+
+    /// Register a custom property, as performed when an `@property` rule is seen.
+    pub fn register(&mut self, name: Name, registration: PropertyRegistration) {
+        self.properties.insert(name, registration);
+    }
+
+    /// Register a custom property from an `@property` rule's descriptors.
+    ///
+    /// `name` is the custom-property name *including* the leading `--`; an invalid
+    /// name or an invalid registration is ignored (the rule has no effect), matching
+    /// the error handling of invalid at-rules elsewhere.
+    pub fn register_from_rule(&mut self, name: &str, syntax: &str, inherits: bool,
+                              initial: Option<&str>) -> Result<(), ()> {
+        let name = try!(parse_name(name));
+        let registration = try!(PropertyRegistration::parse(syntax, inherits, initial));
+        self.register(Atom::from_slice(name), registration);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &Name) -> Option<&PropertyRegistration> {
+        self.properties.get(name)
+    }
+}
+
 pub fn parse(input: &mut Parser) -> Result<SpecifiedValue, ()> {
     let start = input.position();
     let mut references = Some(HashSet::new());
@@ -105,6 +506,24 @@ pub fn parse(input: &mut Parser) -> Result<SpecifiedValue, ()> {
     })
 }
 
+/// Parse the value of a *non-custom* property that contains `var()` references.
+///
+/// Unlike `parse` (which is for custom properties), this records which custom
+/// properties the declaration references so that, when one of those custom
+/// properties changes, only the declarations that actually depend on it need to be
+/// re-substituted. Returns the `first_token_type`, the owned CSS of the value, and
+/// the set of referenced custom property `Name`s. This function does not store the
+/// set anywhere; `build_dependency_index` takes such per-declaration sets and maps
+/// each custom property to the declarations that (transitively) reference it.
+pub fn parse_non_custom_with_var<'i, 't>(input: &mut Parser<'i, 't>)
+                                         -> Result<(TokenSerializationType, String, HashSet<Name>), ()> {
+    let start = input.position();
+    let mut references = Some(HashSet::new());
+    let (first_token_type, _) = try!(parse_declaration_value(input, &mut references));
+    let css = input.slice_from(start).to_owned();
+    Ok((first_token_type, css, references.unwrap()))
+}
+
 /// https://drafts.csswg.org/css-syntax-3/#typedef-declaration-value
 pub fn parse_declaration_value(input: &mut Parser, references: &mut Option<HashSet<Name>>)
                                -> Result<(TokenSerializationType, TokenSerializationType), ()> {
@@ -175,35 +594,81 @@ fn parse_var_function<'i, 't>(input: &mut Parser<'i, 't>, references: &mut Optio
     Ok(())
 }
 
+/// The set of custom properties reachable from `references`, following references
+/// between custom properties recorded in `dependencies`.
+fn transitive_references(references: &HashSet<Name>,
+                         dependencies: &HashMap<Name, HashSet<Name>>) -> HashSet<Name> {
+    let mut result = HashSet::new();
+    let mut stack: Vec<Name> = references.iter().cloned().collect();
+    while let Some(name) = stack.pop() {
+        if result.insert(name.clone()) {
+            if let Some(refs) = dependencies.get(&name) {
+                stack.extend(refs.iter().cloned());
+            }
+        }
+    }
+    result
+}
+
+/// Build an index mapping each custom property name to the set of declarations that
+/// must be re-substituted when that property changes.
+///
+/// `declarations` pairs each declaration (identified by `K`) with the custom
+/// properties it directly references; `dependencies` maps a custom property to the
+/// custom properties *it* references, so a change propagates transitively. This lets
+/// a single `--var` mutation re-substitute only the affected declarations instead of
+/// every property on the element.
+pub fn build_dependency_index<K>(declarations: &[(K, HashSet<Name>)],
+                                 dependencies: &HashMap<Name, HashSet<Name>>)
+                                 -> HashMap<Name, HashSet<K>>
+                                 where K: Clone + Eq + ::std::hash::Hash {
+    let mut index: HashMap<Name, HashSet<K>> = HashMap::new();
+    for &(ref key, ref references) in declarations {
+        for name in transitive_references(references, dependencies) {
+            index.entry(name).or_insert_with(HashSet::new).insert(key.clone());
+        }
+    }
+    index
+}
+
 /// Add one custom property declaration to a map,
 /// unless another with the same name was already there.
-pub fn cascade<'a>(custom_properties: &mut Option<HashMap<&'a Name, BorrowedSpecifiedValue<'a>>>,
-                   inherited: &'a Option<Arc<HashMap<Name, ComputedValue>>>,
+pub fn cascade<'a>(custom_properties: &mut Option<OrderedMap<BorrowedSpecifiedValue<'a>>>,
+                   inherited: &'a Option<Arc<ComputedValuesMap>>,
                    seen: &mut HashSet<&'a Name>,
                    name: &'a Name,
-                   specified_value: &'a DeclaredValue<SpecifiedValue>) {
+                   specified_value: &'a DeclaredValue<SpecifiedValue>,
+                   registry: &'a Registry) {
     let was_not_already_present = seen.insert(name);
     if was_not_already_present {
         let map = match *custom_properties {
             Some(ref mut map) => map,
             None => {
-                *custom_properties = Some(match *inherited {
-                    Some(ref inherited) => inherited.iter().map(|(key, inherited_value)| {
-                        (key, BorrowedSpecifiedValue {
+                let mut map = OrderedMap::new();
+                if let Some(ref inherited) = *inherited {
+                    // Seed the child from the inherited properties in forward cascade
+                    // order, so inherited-then-own ordering is preserved down the tree.
+                    // A registered, non-inheriting property is *not* seeded: unless this
+                    // element declares it, it is absent from the map.
+                    for (key, inherited_value) in inherited.iter_forward() {
+                        if registry.get(key).map_or(false, |reg| !reg.inherits) {
+                            continue;
+                        }
+                        map.insert(key.clone(), BorrowedSpecifiedValue {
                             css: &inherited_value.css,
                             first_token_type: inherited_value.first_token_type,
                             last_token_type: inherited_value.last_token_type,
                             references: None
-                        })
-                    }).collect(),
-                    None => HashMap::new(),
-                });
+                        });
+                    }
+                }
+                *custom_properties = Some(map);
                 custom_properties.as_mut().unwrap()
             }
         };
         match *specified_value {
             DeclaredValue::Value(ref specified_value) => {
-                map.insert(name, BorrowedSpecifiedValue {
+                map.insert(name.clone(), BorrowedSpecifiedValue {
                     css: &specified_value.css,
                     first_token_type: specified_value.first_token_type,
                     last_token_type: specified_value.last_token_type,
@@ -212,19 +677,34 @@ pub fn cascade<'a>(custom_properties: &mut Option<HashMap<&'a Name, BorrowedSpec
             },
             DeclaredValue::WithVariables { .. } => unreachable!(),
             DeclaredValue::Initial => {
-                map.remove(&name);
+                // For a registered property, `initial` resolves to the registered
+                // initial value rather than removing the property.
+                match registry.get(name).and_then(|reg| reg.initial.as_ref()) {
+                    Some(initial) => {
+                        map.insert(name.clone(), BorrowedSpecifiedValue {
+                            css: &initial.css,
+                            first_token_type: initial.first_token_type,
+                            last_token_type: initial.last_token_type,
+                            references: None,
+                        });
+                    }
+                    None => {
+                        map.remove(name);
+                    }
+                }
             }
             DeclaredValue::Inherit => {}  // The inherited value is what we already have.
         }
     }
 }
 
-pub fn finish_cascade(specified_values_map: Option<HashMap<&Name, BorrowedSpecifiedValue>>,
-                      inherited: &Option<Arc<HashMap<Name, ComputedValue>>>)
-                      -> Option<Arc<HashMap<Name, ComputedValue>>> {
+pub fn finish_cascade(specified_values_map: Option<OrderedMap<BorrowedSpecifiedValue>>,
+                      inherited: &Option<Arc<ComputedValuesMap>>,
+                      registry: &Registry)
+                      -> Option<Arc<ComputedValuesMap>> {
     if let Some(mut map) = specified_values_map {
         remove_cycles(&mut map);
-        Some(Arc::new(substitute_all(map, inherited)))
+        Some(Arc::new(substitute_all(map, inherited, registry)))
     } else {
         inherited.clone()
     }
@@ -232,15 +712,15 @@ pub fn finish_cascade(specified_values_map: Option<HashMap<&Name, BorrowedSpecif
 
 /// https://drafts.csswg.org/css-variables/#cycles
 /// The initial value of a custom property is represented by this property not being in the map.
-fn remove_cycles(map: &mut HashMap<&Name, BorrowedSpecifiedValue>) {
+fn remove_cycles(map: &mut OrderedMap<BorrowedSpecifiedValue>) {
     let mut to_remove = HashSet::new();
     {
         let mut visited = HashSet::new();
         let mut stack = Vec::new();
-        for name in map.keys() {
+        for name in &map.index {
             walk(map, name, &mut stack, &mut visited, &mut to_remove);
 
-            fn walk<'a>(map: &HashMap<&'a Name, BorrowedSpecifiedValue<'a>>,
+            fn walk<'a>(map: &'a OrderedMap<BorrowedSpecifiedValue<'a>>,
                         name: &'a Name,
                         stack: &mut Vec<&'a Name>,
                         visited: &mut HashSet<&'a Name>,
@@ -274,17 +754,20 @@ fn remove_cycles(map: &mut HashMap<&Name, BorrowedSpecifiedValue>) {
 }
 
 /// Replace `var()` functions for all custom properties.
-fn substitute_all(specified_values_map: HashMap<&Name, BorrowedSpecifiedValue>,
-                  inherited: &Option<Arc<HashMap<Name, ComputedValue>>>)
-                  -> HashMap<Name, ComputedValue> {
-    let mut computed_values_map = HashMap::new();
+fn substitute_all(specified_values_map: OrderedMap<BorrowedSpecifiedValue>,
+                  inherited: &Option<Arc<ComputedValuesMap>>,
+                  registry: &Registry)
+                  -> ComputedValuesMap {
+    let mut computed_values_map = OrderedMap::new();
     let mut invalid = HashSet::new();
-    for (&name, value) in &specified_values_map {
+    // Substitute in cascade order so the computed map is deterministic.
+    for name in &specified_values_map.index {
+        let value = specified_values_map.get(name).unwrap();
         // If this value is invalid at computed-time it won’t be inserted in computed_values_map.
         // Nothing else to do.
         let _ = substitute_one(
             name, value, &specified_values_map, inherited, None,
-            &mut computed_values_map, &mut invalid);
+            &mut computed_values_map, &mut invalid, registry);
     }
     computed_values_map
 }
@@ -295,11 +778,12 @@ fn substitute_all(specified_values_map: HashMap<&Name, BorrowedSpecifiedValue>,
 /// or `Ok(last_token_type that was pushed to partial_computed_value)` otherwise.
 fn substitute_one(name: &Name,
                   specified_value: &BorrowedSpecifiedValue,
-                  specified_values_map: &HashMap<&Name, BorrowedSpecifiedValue>,
-                  inherited: &Option<Arc<HashMap<Name, ComputedValue>>>,
+                  specified_values_map: &OrderedMap<BorrowedSpecifiedValue>,
+                  inherited: &Option<Arc<ComputedValuesMap>>,
                   partial_computed_value: Option<&mut ComputedValue>,
-                  computed_values_map: &mut HashMap<Name, ComputedValue>,
-                  invalid: &mut HashSet<Name>)
+                  computed_values_map: &mut ComputedValuesMap,
+                  invalid: &mut HashSet<Name>,
+                  registry: &Registry)
                   -> Result<TokenSerializationType, ()> {
     if let Some(computed_value) = computed_values_map.get(name) {
         if let Some(partial_computed_value) = partial_computed_value {
@@ -320,7 +804,8 @@ fn substitute_one(name: &Name,
             &mut |name, partial_computed_value| {
                 if let Some(other_specified_value) = specified_values_map.get(name) {
                     substitute_one(name, other_specified_value, specified_values_map, inherited,
-                                   Some(partial_computed_value), computed_values_map, invalid)
+                                   Some(partial_computed_value), computed_values_map, invalid,
+                                   registry)
                 } else {
                     Err(())
                 }
@@ -328,6 +813,12 @@ fn substitute_one(name: &Name,
         );
         if let Ok(last_token_type) = result {
             partial_computed_value.push_from(position, &input, last_token_type);
+            // Close any function/bracket/string left open at the end of the input.
+            let (css, closed_last) = close_unclosed_blocks(&partial_computed_value.css);
+            partial_computed_value.css = css;
+            if let Some(last) = closed_last {
+                partial_computed_value.last_token_type = last;
+            }
             partial_computed_value
         } else {
             // Invalid at computed-value time. Use the inherited value.
@@ -346,6 +837,20 @@ fn substitute_one(name: &Name,
             last_token_type: specified_value.last_token_type,
         }
     };
+    // For a registered property, validate the substituted value against its syntax.
+    // On failure, fall back to the registered initial value ("guaranteed-invalid").
+    let computed_value = match registry.get(name) {
+        Some(registration) if !registration.syntax.validate(&computed_value.css) => {
+            match registration.initial {
+                Some(ref initial) => initial.clone(),
+                None => {
+                    invalid.insert(name.clone());
+                    return Err(())
+                }
+            }
+        }
+        _ => computed_value,
+    };
     if let Some(partial_computed_value) = partial_computed_value {
         partial_computed_value.push_variable(&computed_value)
     }
@@ -434,19 +939,81 @@ fn substitute_block<F>(input: &mut Parser,
             _ => last_token_type = token.serialization_type()
         }
     }
-    // FIXME: deal with things being implicitly closed at the end of the input. E.g.
-    // ```html
-    // <div style="--color: rgb(0,0,0">
-    // <p style="background: var(--color) var(--image) top left; --image: url('a.png"></p>
-    // </div>
-    // ```
     Ok(last_token_type)
 }
 
+/// Per CSS Syntax "consume a declaration", a function/bracket/string/url left open at
+/// the end of the input is implicitly closed. A value substituted into a `var()`
+/// reference can therefore be missing its trailing `)`, `]`, `}`, closing quote or
+/// url `)`. This appends the synthesized closing tokens so the produced `css` is
+/// well-formed, and reports the serialization type of the last token appended (if any)
+/// so `last_token_type` can be corrected by the caller.
+fn close_unclosed_blocks(css: &str) -> (String, Option<TokenSerializationType>) {
+    let mut result = css.to_owned();
+    let mut stack: Vec<char> = Vec::new();
+    let mut string_quote: Option<char> = None;
+    let mut in_comment = false;
+    let mut escaped = false;
+    let mut chars = css.chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_comment {
+            if c == '*' && chars.peek() == Some(&'/') {
+                chars.next();
+                in_comment = false;
+            }
+            continue;
+        }
+        if let Some(quote) = string_quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == quote {
+                string_quote = None;
+            }
+            continue;
+        }
+        match c {
+            '\\' => { chars.next(); }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                in_comment = true;
+            }
+            '"' | '\'' => string_quote = Some(c),
+            '(' => stack.push(')'),
+            '[' => stack.push(']'),
+            '{' => stack.push('}'),
+            ')' | ']' | '}' => {
+                if stack.last() == Some(&c) {
+                    stack.pop();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut last_token_type = None;
+    // An unterminated string (e.g. inside `url('a.png`) gets its closing quote.
+    if let Some(quote) = string_quote {
+        result.push(quote);
+        last_token_type = Some(Token::QuotedString(Cow::Borrowed("")).serialization_type());
+    }
+    if in_comment {
+        result.push_str("*/");
+    }
+    // Synthesize the missing closers, innermost first.
+    while let Some(closer) = stack.pop() {
+        result.push(closer);
+        // CloseParenthesis/CloseSquareBracket/CloseCurlyBracket share a serialization type.
+        last_token_type = Some(Token::CloseParenthesis.serialization_type());
+    }
+    (result, last_token_type)
+}
+
 /// Replace `var()` functions for a non-custom property.
 /// Return `Err(())` for invalid at computed time.
 pub fn substitute(input: &str, first_token_type: TokenSerializationType,
-                  computed_values_map: &Option<Arc<HashMap<Name, ComputedValue>>>)
+                  computed_values_map: &Option<Arc<ComputedValuesMap>>)
                   -> Result<String, ()> {
     let mut substituted = ComputedValue::empty();
     let mut input = Parser::new(input);
@@ -462,5 +1029,135 @@ pub fn substitute(input: &str, first_token_type: TokenSerializationType,
         }
     ));
     substituted.push_from(position, &input, last_token_type);
-    Ok(substituted.css)
+    let (css, _) = close_unclosed_blocks(&substituted.css);
+    Ok(css)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PropertyRegistration, close_unclosed_blocks, parse_syntax};
+
+    fn closed(input: &str) -> String {
+        close_unclosed_blocks(input).0
+    }
+
+    fn valid(syntax: &str, css: &str) -> bool {
+        parse_syntax(syntax).unwrap().validate(css)
+    }
+
+    #[test]
+    fn syntax_universal_accepts_anything() {
+        assert!(valid("*", "banana"));
+        assert!(valid("*", "1px 2px"));
+    }
+
+    #[test]
+    fn syntax_length_list() {
+        assert!(valid("<length>+", "1px"));
+        assert!(valid("<length>+", "1px 2px 3px"));
+        // A comma-separated list is not a space-separated one.
+        assert!(!valid("<length>+", "1px, 2px"));
+        // A percentage is not a length.
+        assert!(!valid("<length>+", "1px 2%"));
+    }
+
+    #[test]
+    fn syntax_comma_separated_list() {
+        assert!(valid("<number>#", "1, 2, 3"));
+        assert!(!valid("<number>#", "1 2 3"));
+    }
+
+    #[test]
+    fn syntax_alternation() {
+        assert!(valid("<color> | <number>", "red"));
+        assert!(valid("<color> | <number>", "42"));
+        assert!(!valid("<color> | <number>", "1px"));
+    }
+
+    #[test]
+    fn syntax_color_rejects_arbitrary_ident() {
+        assert!(valid("<color>", "red"));
+        assert!(valid("<color>", "#fff"));
+        assert!(valid("<color>", "rgb(0, 0, 0)"));
+        assert!(!valid("<color>", "banana"));
+        assert!(!valid("<color>", "foo(1)"));
+    }
+
+    #[test]
+    fn parse_non_custom_records_references() {
+        use cssparser::Parser;
+        use string_cache::Atom;
+        let mut input = Parser::new("var(--a) top var(--b)");
+        let (_, css, references) = super::parse_non_custom_with_var(&mut input).unwrap();
+        assert_eq!(css, "var(--a) top var(--b)");
+        assert!(references.contains(&Atom::from_slice("a")));
+        assert!(references.contains(&Atom::from_slice("b")));
+        assert_eq!(references.len(), 2);
+    }
+
+    #[test]
+    fn dependency_index_is_transitive() {
+        use std::collections::{HashMap, HashSet};
+        use string_cache::Atom;
+
+        let name = |s: &str| Atom::from_slice(s);
+        let set = |names: &[&str]| names.iter().map(|n| name(n)).collect::<HashSet<_>>();
+
+        // Declaration "bg" references --a; --a references --b in turn.
+        let declarations = vec![("bg", set(&["a"]))];
+        let mut dependencies = HashMap::new();
+        dependencies.insert(name("a"), set(&["b"]));
+
+        let index = super::build_dependency_index(&declarations, &dependencies);
+        // Changing either --a or (transitively) --b must re-substitute "bg".
+        assert!(index.get(&name("a")).unwrap().contains("bg"));
+        assert!(index.get(&name("b")).unwrap().contains("bg"));
+        // A property nothing depends on has no dependents.
+        assert!(index.get(&name("c")).is_none());
+    }
+
+    #[test]
+    fn registration_requires_valid_initial() {
+        // A non-universal syntax needs an initial value that parses against it.
+        assert!(PropertyRegistration::parse("<length>", false, Some("0px")).is_ok());
+        assert!(PropertyRegistration::parse("<length>", false, Some("red")).is_err());
+        assert!(PropertyRegistration::parse("<length>", false, None).is_err());
+        // The universal syntax may omit the initial value.
+        assert!(PropertyRegistration::parse("*", true, None).is_ok());
+    }
+
+    #[test]
+    fn unclosed_parenthesis() {
+        assert_eq!(closed("rgb(0, 0, 0"), "rgb(0, 0, 0)");
+    }
+
+    #[test]
+    fn unclosed_square_bracket() {
+        assert_eq!(closed("[a b"), "[a b]");
+    }
+
+    #[test]
+    fn unclosed_curly_bracket() {
+        assert_eq!(closed("{a: b"), "{a: b}");
+    }
+
+    #[test]
+    fn unterminated_url() {
+        assert_eq!(closed("url('a.png"), "url('a.png')");
+    }
+
+    #[test]
+    fn unterminated_string() {
+        assert_eq!(closed("\"abc"), "\"abc\"");
+    }
+
+    #[test]
+    fn nested_blocks_closed_in_order() {
+        assert_eq!(closed("a(b[c"), "a(b[c])");
+    }
+
+    #[test]
+    fn already_balanced_is_unchanged() {
+        assert_eq!(closed("rgb(0, 0, 0)"), "rgb(0, 0, 0)");
+    }
 }